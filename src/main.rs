@@ -29,9 +29,11 @@ lockstep will also look into Cargo.lock to check for outdated revisions there.
 
 if nothing is required, lockstep won't print anything.
 
-# TODO
+the set of repos, their inter-repo pins, and the artifact-server URL live in
+`lockstep.toml`, so adding a repo (for example opte) is a config edit.
 
-opte support is missing
+with `--format json` the findings are printed as a single JSON array instead,
+which is `[]` when nothing is required.
 */
 
 use std::cmp::Ordering;
@@ -42,38 +44,163 @@ use std::hash::Hash;
 use std::path::Path;
 use url::Url;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use cargo_lock::package::SourceKind;
 use cargo_toml::Manifest;
 use glob::glob;
 use reqwest::blocking::Client;
 
+use crates_index::GitIndex;
+use semver::{Version, VersionReq};
+
 use omicron_zone_package::config::*;
 use omicron_zone_package::package::*;
 
+/// Whether lockstep should only print the changes it would make, or perform
+/// them in place. `cargo_toml::Manifest` is a lossy parse, so the edits are
+/// driven by `toml_edit` against the raw document, the same way cargo-edit
+/// keeps its semantic manifest separate from the on-disk `toml_mut` one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    DryRun,
+    Apply,
+}
+
+/// How findings are surfaced: free-form English for a human, or a JSON array
+/// for CI and other tooling to consume.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// The category of a single finding. Serialized kebab-case so the JSON output
+/// reads e.g. `"cargo-toml-rev"`.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FindingKind {
+    CargoTomlRev,
+    CargoTomlReq,
+    CargoLockRev,
+    ManifestSha256,
+    ManifestRev,
+    ImagePending,
+}
+
+/// A single machine-readable record of something lockstep noticed.
+#[derive(Clone, Debug, serde::Serialize)]
+struct Finding {
+    kind: FindingKind,
+    repo: String,
+    file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+}
+
+/// Collects findings and emits them in the requested format. In text mode each
+/// finding is printed as it is reported; in JSON mode they are buffered and
+/// written as a single array by [`Reporter::finish`].
+struct Reporter {
+    format: OutputFormat,
+    findings: Vec<Finding>,
+}
+
+impl Reporter {
+    fn new(format: OutputFormat) -> Self {
+        Reporter {
+            format,
+            findings: Vec::new(),
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// Record a finding, printing its human-readable form immediately in text
+    /// mode.
+    fn report(&mut self, text: &str, finding: Finding) {
+        if self.is_text() {
+            println!("{}", text);
+        }
+        self.findings.push(finding);
+    }
+
+    /// In JSON mode, print the buffered findings as an array.
+    fn finish(&self) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&self.findings)?);
+        }
+        Ok(())
+    }
+}
+
+/// Set a single scalar value inside a TOML document, navigating `keys` from the
+/// document root, while leaving comments, key ordering and whitespace
+/// untouched. Used to rewrite a `rev`/`commit`/`sha256` without reformatting
+/// the rest of the file.
+fn set_toml_value(path: &str, keys: &[&str], value: &str) -> Result<()> {
+    let mut document = std::fs::read_to_string(path)?
+        .parse::<toml_edit::DocumentMut>()
+        .with_context(|| format!("parsing {}", path))?;
+
+    let mut item = document.as_item_mut();
+    for key in keys {
+        item = &mut item[*key];
+    }
+    *item = toml_edit::value(value);
+
+    std::fs::write(path, document.to_string())?;
+
+    Ok(())
+}
+
 /// Recursively search each Cargo.toml to see if a package's revision needs
 /// updating. Print out an instruction if it does, and return if an update is
-/// required.
+/// required. In `Mode::Apply` the `rev` is also rewritten in place.
 fn compare_cargo_toml_revisions(
     sub_directory: &str,
     cargo_manifest: &Manifest,
     package: &str,
     ensure_rev: &str,
+    mode: Mode,
+    reporter: &mut Reporter,
 ) -> Result<bool> {
     let mut update_required = false;
 
     let cargo_path = format!("./{}/Cargo.toml", sub_directory);
     for (dep_key, dep) in &cargo_manifest.dependencies {
         if let Some(detail) = dep.detail() {
-            // TODO currently does not check for crates.io, just git
+            // crates.io version reqs are handled by compare_cargo_toml_versions
             if let Some(git) = &detail.git {
                 if git.ends_with(package) {
                     if let Some(rev) = &detail.rev {
                         if rev != ensure_rev {
-                            println!(
-                                "update {:?} {:?} rev from {} to {}",
-                                cargo_path, dep_key, rev, ensure_rev,
+                            reporter.report(
+                                &format!(
+                                    "update {:?} {:?} rev from {} to {}",
+                                    cargo_path, dep_key, rev, ensure_rev,
+                                ),
+                                Finding {
+                                    kind: FindingKind::CargoTomlRev,
+                                    repo: package.to_string(),
+                                    file: cargo_path.clone(),
+                                    dependency: Some(dep_key.clone()),
+                                    from: Some(rev.clone()),
+                                    to: Some(ensure_rev.to_string()),
+                                },
                             );
+                            if mode == Mode::Apply {
+                                set_toml_value(
+                                    &cargo_path,
+                                    &["dependencies", dep_key, "rev"],
+                                    ensure_rev,
+                                )?;
+                            }
                             update_required = true;
                         }
                     }
@@ -85,15 +212,32 @@ fn compare_cargo_toml_revisions(
     if let Some(workspace) = &cargo_manifest.workspace {
         for (dep_key, dep) in &workspace.dependencies {
             if let Some(detail) = dep.detail() {
-                // TODO currently does not check for crates.io, just git
+                // crates.io version reqs are handled by compare_cargo_toml_versions
                 if let Some(git) = &detail.git {
                     if git.ends_with(package) {
                         if let Some(rev) = &detail.rev {
                             if rev != ensure_rev {
-                                println!(
-                                    "update {:?} {:?} rev from {} to {}",
-                                    cargo_path, dep_key, rev, ensure_rev,
+                                reporter.report(
+                                    &format!(
+                                        "update {:?} {:?} rev from {} to {}",
+                                        cargo_path, dep_key, rev, ensure_rev,
+                                    ),
+                                    Finding {
+                                        kind: FindingKind::CargoTomlRev,
+                                        repo: package.to_string(),
+                                        file: cargo_path.clone(),
+                                        dependency: Some(dep_key.clone()),
+                                        from: Some(rev.clone()),
+                                        to: Some(ensure_rev.to_string()),
+                                    },
                                 );
+                                if mode == Mode::Apply {
+                                    set_toml_value(
+                                        &cargo_path,
+                                        &["workspace", "dependencies", dep_key, "rev"],
+                                        ensure_rev,
+                                    )?;
+                                }
                                 update_required = true;
                             }
                         }
@@ -127,6 +271,8 @@ fn compare_cargo_toml_revisions(
                     &sub_cargo_manifest,
                     package,
                     ensure_rev,
+                    mode,
+                    reporter,
                 )?;
             }
         }
@@ -180,6 +326,270 @@ fn get_explicit_dependencies(
     Ok(())
 }
 
+/// Split a version requirement into its leading operator (e.g. `^`, `=`, `~`,
+/// `>=`) and the bare version, so a proposed bump can keep the original style
+/// the way cargo-edit's `set_dep_version` does.
+fn split_req_operator(req: &str) -> (&str, &str) {
+    let trimmed = req.trim();
+    let op_len = trimmed
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    (trimmed[..op_len].trim(), trimmed[op_len..].trim())
+}
+
+/// Return the latest non-yanked, non-prerelease version of `name` in the
+/// registry index, and the latest one that matches `req` (cargo-edit's
+/// `get_latest_dependency` / `get_compatible_dependency`).
+fn registry_latest(
+    index: &GitIndex,
+    name: &str,
+    req: &VersionReq,
+) -> Result<(Option<Version>, Option<Version>)> {
+    let krate = match index.crate_(name) {
+        Some(krate) => krate,
+        None => return Ok((None, None)),
+    };
+
+    let mut latest: Option<Version> = None;
+    let mut compatible: Option<Version> = None;
+
+    for version in krate.versions() {
+        if version.is_yanked() {
+            continue;
+        }
+
+        let parsed = match Version::parse(version.version()) {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+
+        if !parsed.pre.is_empty() {
+            continue;
+        }
+
+        if latest.as_ref().map_or(true, |l| parsed > *l) {
+            latest = Some(parsed.clone());
+        }
+
+        if req.matches(&parsed) && compatible.as_ref().map_or(true, |c| parsed > *c) {
+            compatible = Some(parsed);
+        }
+    }
+
+    Ok((latest, compatible))
+}
+
+/// Recursively search each Cargo.toml for crates.io version-requirement
+/// dependencies and report (and, in `Mode::Apply`, rewrite) any that are behind
+/// the latest version still matching their requirement (an in-range bump, not
+/// necessarily the crate's absolute latest release). `=`-pinned requirements
+/// are treated as intentionally frozen: they are reported but never auto-bumped.
+fn compare_cargo_toml_versions(
+    sub_directory: &str,
+    cargo_manifest: &Manifest,
+    index: &GitIndex,
+    mode: Mode,
+    reporter: &mut Reporter,
+) -> Result<bool> {
+    let mut update_required = false;
+
+    let cargo_path = format!("./{}/Cargo.toml", sub_directory);
+    let check = |dep_key: &str,
+                 dep: &cargo_toml::Dependency,
+                 base: &[&str],
+                 reporter: &mut Reporter|
+     -> Result<bool> {
+        // git deps are handled by compare_cargo_toml_revisions; skip path and
+        // requirement-less (inherited) deps.
+        if let Some(detail) = dep.detail() {
+            if detail.git.is_some() || detail.path.is_some() {
+                return Ok(false);
+            }
+        }
+
+        let req_str = dep.req();
+        if req_str == "*" {
+            return Ok(false);
+        }
+
+        let req = match VersionReq::parse(req_str) {
+            Ok(req) => req,
+            Err(_) => return Ok(false),
+        };
+
+        // a requirement with more than one comparator (e.g. ">= 1.2, < 2") would lose its
+        // upper bound if we rewrote it as `op + <version>`; leave those for a human
+        if req.comparators.len() > 1 {
+            if reporter.is_text() {
+                println!(
+                    "{:?} {:?} req {} has multiple comparators; skipping automatic rewrite",
+                    cargo_path, dep_key, req_str,
+                );
+            }
+            return Ok(false);
+        }
+
+        // a renamed dependency resolves against its real crate name
+        let name = dep
+            .detail()
+            .and_then(|detail| detail.package.as_deref())
+            .unwrap_or(dep_key);
+
+        let (latest, compatible) = registry_latest(index, name, &req)?;
+        let latest = match latest {
+            Some(latest) => latest,
+            None => return Ok(false),
+        };
+
+        let (op, _) = split_req_operator(req_str);
+
+        // `=`-pinned requirements are intentionally frozen: never auto-bump them, but
+        // still surface a finding (not just text) when a newer version exists, so CI
+        // consuming `--format json` sees that a human needs to look at it.
+        if op == "=" {
+            if req.matches(&latest) {
+                return Ok(false);
+            }
+            reporter.report(
+                &format!(
+                    "{:?} {:?} req {} is pinned but {} is available; leaving frozen",
+                    cargo_path, dep_key, req_str, latest,
+                ),
+                Finding {
+                    kind: FindingKind::CargoTomlReq,
+                    repo: sub_directory.to_string(),
+                    file: cargo_path.clone(),
+                    dependency: Some(dep_key.to_string()),
+                    from: Some(req_str.to_string()),
+                    to: None,
+                },
+            );
+            return Ok(false);
+        }
+
+        // Compare the latest version still matching this requirement against the
+        // requirement's own floor, not whether the absolute `latest` satisfies the
+        // requirement: the common case is a newer in-range version with no breaking
+        // release at all, e.g. `^1.0` -> `^1.2`, which `req.matches(&latest)` alone
+        // would never catch since it only fires once a breaking release exists too.
+        let compatible = match compatible {
+            Some(compatible) => compatible,
+            None => {
+                reporter.report(
+                    &format!(
+                        "{:?} {:?} req {} matches no published version of {}; {} is available but would be a breaking change",
+                        cargo_path, dep_key, req_str, name, latest,
+                    ),
+                    Finding {
+                        kind: FindingKind::CargoTomlReq,
+                        repo: sub_directory.to_string(),
+                        file: cargo_path.clone(),
+                        dependency: Some(dep_key.to_string()),
+                        from: Some(req_str.to_string()),
+                        to: None,
+                    },
+                );
+                return Ok(false);
+            }
+        };
+
+        let new_req = format!("{}{}", op, compatible);
+        let floor = req
+            .comparators
+            .first()
+            .map(|c| Version::new(c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0)));
+        if floor.map_or(true, |floor| compatible <= floor) {
+            // already at the best version this requirement allows; a newer major may
+            // exist, but crossing that boundary is a human decision, not an auto-bump
+            if !req.matches(&latest) {
+                reporter.report(
+                    &format!(
+                        "{:?} {:?} req {} is already at the newest version it allows; {} {} is available but would be a breaking change",
+                        cargo_path, dep_key, req_str, name, latest,
+                    ),
+                    Finding {
+                        kind: FindingKind::CargoTomlReq,
+                        repo: sub_directory.to_string(),
+                        file: cargo_path.clone(),
+                        dependency: Some(dep_key.to_string()),
+                        from: Some(req_str.to_string()),
+                        to: None,
+                    },
+                );
+            }
+            return Ok(false);
+        }
+
+        reporter.report(
+            &format!(
+                "update {:?} {:?} req from {} to {}",
+                cargo_path, dep_key, req_str, new_req,
+            ),
+            Finding {
+                kind: FindingKind::CargoTomlReq,
+                repo: sub_directory.to_string(),
+                file: cargo_path.clone(),
+                dependency: Some(dep_key.to_string()),
+                from: Some(req_str.to_string()),
+                to: Some(new_req.clone()),
+            },
+        );
+        if mode == Mode::Apply {
+            // a simple `name = "req"` dep stores the requirement as the value
+            // itself; a detailed one keeps it under `version`.
+            let mut table_path: Vec<&str> = base.to_vec();
+            table_path.push(dep_key);
+            if dep.detail().is_some() {
+                table_path.push("version");
+            }
+            set_toml_value(&cargo_path, &table_path, &new_req)?;
+        }
+
+        Ok(true)
+    };
+
+    for (dep_key, dep) in &cargo_manifest.dependencies {
+        update_required |= check(dep_key, dep, &["dependencies"], reporter)?;
+    }
+
+    if let Some(workspace) = &cargo_manifest.workspace {
+        for (dep_key, dep) in &workspace.dependencies {
+            update_required |= check(dep_key, dep, &["workspace", "dependencies"], reporter)?;
+        }
+
+        for member in &workspace.members {
+            // use glob to support members that look like "lib/*"
+            let path = format!("./{}/{}/Cargo.toml", sub_directory, member);
+            for sub_cargo_path in glob(&path).expect("failed to glob pattern") {
+                let sub_cargo_path = match sub_cargo_path {
+                    Ok(path) => path,
+                    Err(e) => {
+                        return Err(anyhow!(e));
+                    }
+                };
+
+                let sub_cargo_manifest = match Manifest::from_path(&sub_cargo_path) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        eprintln!("error with {:?}: {}", &sub_cargo_path, e);
+                        continue;
+                    }
+                };
+
+                update_required |= compare_cargo_toml_versions(
+                    sub_cargo_path.parent().unwrap().to_str().unwrap(),
+                    &sub_cargo_manifest,
+                    index,
+                    mode,
+                    reporter,
+                )?;
+            }
+        }
+    }
+
+    Ok(update_required)
+}
+
 // Implement SourceID like rust-lang/Cargo, not like in rustsec/rustsec (read:
 // with manual impls)
 #[derive(Clone, Debug, Eq)]
@@ -220,13 +630,93 @@ impl Hash for MySourceId {
     }
 }
 
+/// Copy a directory tree into `dst`, skipping VCS metadata and build output.
+/// Used to give `cargo update` a full throwaway copy of a workspace, since a
+/// workspace root's `members` paths need to resolve to real member manifests.
+fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if file_name == ".git" || file_name == "target" {
+            continue;
+        }
+
+        let dst_path = dst.join(&file_name);
+        if entry.file_type()?.is_dir() {
+            copy_dir_recursive(&entry.path(), &dst_path)?;
+        } else {
+            std::fs::copy(entry.path(), &dst_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run `cargo update --precise <latest_rev> -p <package_name>` for a stale
+/// lockfile entry, but do it against a throwaway copy of the project first so a
+/// failed resolution never corrupts the real checkout. Only once the temp
+/// lockfile resolves and actually changes do we copy it back over the real
+/// `Cargo.lock`. This is the temp-project approach cargo-outdated uses.
+///
+/// The whole manifest directory is copied, not just `Cargo.toml`/`Cargo.lock`:
+/// omicron and propolis are workspaces whose root manifest references members
+/// by path, and `cargo update` needs those member manifests on disk to load
+/// the workspace at all.
+fn update_cargo_lock_rev(sub_directory: &str, package_name: &str, latest_rev: &str) -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+
+    copy_dir_recursive(Path::new(sub_directory), temp_dir.path())
+        .with_context(|| format!("copying {} into temp project", sub_directory))?;
+
+    let real_lock = format!("{}/Cargo.lock", sub_directory);
+    let temp_lock = temp_dir.path().join("Cargo.lock");
+
+    let output = std::process::Command::new("cargo")
+        .arg("update")
+        .arg("--precise")
+        .arg(latest_rev)
+        .arg("-p")
+        .arg(package_name)
+        .current_dir(temp_dir.path())
+        .output()
+        .with_context(|| format!("running cargo update for {}", package_name))?;
+
+    if !output.status.success() {
+        // Surface resolution failures (conflicting SourceIds, yanked revs)
+        // rather than leaving the checkout in an unknown state.
+        bail!(
+            "failed to update {} to {} in {}: {}",
+            package_name,
+            latest_rev,
+            sub_directory,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+
+    let updated_lock = std::fs::read_to_string(&temp_lock)?;
+    if updated_lock != std::fs::read_to_string(&real_lock)? {
+        std::fs::write(&real_lock, updated_lock)
+            .with_context(|| format!("writing updated {}", real_lock))?;
+    }
+
+    Ok(())
+}
+
+/// In `Mode::Apply`, also fix up stale lockfile entries via
+/// [`update_cargo_lock_rev`]; otherwise just report them. Returns whether any
+/// stale entry was found.
 fn check_cargo_lock_revisions(
     sub_directory: &str,
     latest_revs: &BTreeMap<String, String>,
-) -> Result<()> {
+    mode: Mode,
+    reporter: &mut Reporter,
+) -> Result<bool> {
     use cargo_lock::package;
     use cargo_lock::Lockfile;
 
+    let mut update_required = false;
     let cargo_lockfile = Lockfile::load(format!("{}/Cargo.lock", sub_directory))?;
     let dependencies = {
         let mut dependencies = HashSet::new();
@@ -251,10 +741,28 @@ fn check_cargo_lock_revisions(
                                     // only suggest running `cargo update -p`
                                     // for packages in a Cargo.toml
                                     if dependencies.contains(&package.name.to_string()) {
-                                        println!(
-                                            "{}/Cargo.lock has old rev for {} {}! update {} to {}",
-                                            sub_directory, repo, package.name, precise, latest_rev,
+                                        reporter.report(
+                                            &format!(
+                                                "{}/Cargo.lock has old rev for {} {}! update {} to {}",
+                                                sub_directory, repo, package.name, precise, latest_rev,
+                                            ),
+                                            Finding {
+                                                kind: FindingKind::CargoLockRev,
+                                                repo: repo.to_string(),
+                                                file: format!("{}/Cargo.lock", sub_directory),
+                                                dependency: Some(package.name.to_string()),
+                                                from: Some(precise.to_string()),
+                                                to: Some(latest_rev.clone()),
+                                            },
                                         );
+                                        update_required = true;
+                                        if mode == Mode::Apply {
+                                            update_cargo_lock_rev(
+                                                sub_directory,
+                                                &package.name.to_string(),
+                                                latest_rev,
+                                            )?;
+                                        }
                                     }
                                 }
                             } else {
@@ -275,7 +783,7 @@ fn check_cargo_lock_revisions(
                 if existing_source.precise == my_source_id.precise {
                     sources.insert(my_source_id);
                 } else {
-                    panic!(
+                    bail!(
                         "{}/Cargo.lock has a mismatch for {:?} != {:?}!",
                         sub_directory, existing_source, my_source_id,
                     );
@@ -286,98 +794,164 @@ fn check_cargo_lock_revisions(
         }
     }
 
-    for source in sources {
-        println!("{}/Cargo.lock has source {:?}", sub_directory, source);
-    }
-
-    Ok(())
-}
-
-fn main() -> Result<()> {
-    let client = Client::new();
-    let mut update_required = false;
-
-    // Check we're in a location that contains checkouts of relevant repos
-    for repo in &["crucible", "propolis", "omicron", "maghemite", "dendrite"] {
-        if !Path::new(&repo).exists() {
-            bail!("cannot find your local checkout of {}!", repo);
+    if reporter.is_text() {
+        for source in sources {
+            println!("{}/Cargo.lock has source {:?}", sub_directory, source);
         }
     }
 
-    // The latest revisions of repos
-    let mut latest_revs: BTreeMap<String, String> = BTreeMap::default();
-
-    // Get the crucible and propolis revisions in the checked out directories
-    let crucible_repo = git2::Repository::open("crucible")?;
-    let crucible_rev: git2::Oid = crucible_repo.head()?.target().unwrap();
-
-    latest_revs.insert("crucible".to_string(), crucible_rev.to_string());
+    Ok(update_required)
+}
 
-    let propolis_repo = git2::Repository::open("propolis")?;
-    let propolis_rev: git2::Oid = propolis_repo.head()?.target().unwrap();
+/// Declarative description of the repos lockstep keeps in sync, loaded from
+/// `lockstep.toml`. Adding a new repo (e.g. opte) or a new inter-repo pin is a
+/// config edit rather than a code change.
+#[derive(Debug, serde::Deserialize)]
+struct LockstepConfig {
+    /// Artifact-server template with `{repo}`, `{commit}` and `{name}`
+    /// placeholders, used to check that a repo's images have been built.
+    image_url: String,
+    /// The repo whose `package-manifest.toml` pins the prebuilt images.
+    manifest_repo: String,
+    #[serde(rename = "repo")]
+    repos: Vec<RepoConfig>,
+    #[serde(rename = "propagate", default)]
+    propagations: Vec<Propagation>,
+}
 
-    latest_revs.insert("propolis".to_string(), propolis_rev.to_string());
+#[derive(Debug, serde::Deserialize)]
+struct RepoConfig {
+    name: String,
+    /// Don't check the artifact server for this repo's images yet.
+    #[serde(default)]
+    skip_image: bool,
+}
 
-    let maghemite_repo = git2::Repository::open("maghemite")?;
-    let maghemite_rev: git2::Oid = maghemite_repo.head()?.target().unwrap();
+/// An edge in the dependency DAG: the HEAD of `from` must be pinned in `into`'s
+/// Cargo.toml (and, for the manifest repo, its package-manifest.toml).
+#[derive(Debug, serde::Deserialize)]
+struct Propagation {
+    from: String,
+    into: String,
+}
 
-    latest_revs.insert("maghemite".to_string(), maghemite_rev.to_string());
+/// Topologically order the repos that participate in the propagation DAG so an
+/// upstream repo is always visited before the repos that pin it.
+fn topological_order(propagations: &[Propagation]) -> Result<Vec<String>> {
+    use std::collections::BTreeSet;
 
-    let dendrite_repo = git2::Repository::open("dendrite")?;
-    let dendrite_rev: git2::Oid = dendrite_repo.head()?.target().unwrap();
+    let mut nodes: BTreeSet<&str> = BTreeSet::new();
+    for propagation in propagations {
+        nodes.insert(propagation.from.as_str());
+        nodes.insert(propagation.into.as_str());
+    }
 
-    latest_revs.insert("dendrite".to_string(), dendrite_rev.to_string());
+    let mut resolved: BTreeSet<&str> = BTreeSet::new();
+    let mut order: Vec<String> = Vec::new();
+
+    while resolved.len() < nodes.len() {
+        // a node is ready once every `from` that feeds it has been resolved
+        let ready: Vec<&str> = nodes
+            .iter()
+            .copied()
+            .filter(|node| !resolved.contains(node))
+            .filter(|node| {
+                propagations
+                    .iter()
+                    .filter(|propagation| &propagation.into == node)
+                    .all(|propagation| resolved.contains(propagation.from.as_str()))
+            })
+            .collect();
+
+        if ready.is_empty() {
+            bail!("lockstep.toml propagation DAG has a cycle");
+        }
 
-    let omicron_repo = git2::Repository::open("omicron")?;
-    let omicron_rev: git2::Oid = omicron_repo.head()?.target().unwrap();
+        for node in ready {
+            resolved.insert(node);
+            order.push(node.to_string());
+        }
+    }
 
-    latest_revs.insert("omicron".to_string(), omicron_rev.to_string());
+    Ok(order)
+}
 
-    // Check the revs in crucible's Cargo.lock
-    check_cargo_lock_revisions("crucible", &latest_revs)?;
+/// Run every check, reporting findings through `reporter`, and return whether
+/// any update is required.
+fn run(mode: Mode, reporter: &mut Reporter) -> Result<bool> {
+    let client = Client::new();
+    let mut update_required = false;
 
-    // Ensure propolis uses this crucible revision
-    update_required |= compare_cargo_toml_revisions(
-        "propolis",
-        &Manifest::from_path("./propolis/Cargo.toml")?,
-        "crucible",
-        &crucible_rev.to_string(),
-    )?;
+    // Load the declarative description of which repos participate, how their
+    // HEADs propagate into each other, and where images are published.
+    let config: LockstepConfig =
+        toml::from_str(&std::fs::read_to_string("./lockstep.toml")?)?;
 
-    if update_required {
-        return Ok(());
+    // Check we're in a location that contains checkouts of relevant repos
+    for repo in &config.repos {
+        if !Path::new(&repo.name).exists() {
+            bail!("cannot find your local checkout of {}!", repo.name);
+        }
     }
 
-    // Check the revs in propolis' Cargo.lock
-    check_cargo_lock_revisions("propolis", &latest_revs)?;
-
-    // Check if omicron needs to:
-    // - update crucible cargo revs
-    // - update propolis cargo revs
-
-    check_cargo_lock_revisions("omicron", &latest_revs)?;
+    // The latest revision of each repo, from its local checkout's HEAD
+    let mut latest_revs: BTreeMap<String, String> = BTreeMap::default();
+    for repo in &config.repos {
+        let git_repo = git2::Repository::open(&repo.name)?;
+        let rev: git2::Oid = git_repo.head()?.target().unwrap();
+        latest_revs.insert(repo.name.clone(), rev.to_string());
+    }
 
-    update_required |= compare_cargo_toml_revisions(
-        "omicron",
-        &Manifest::from_path("./omicron/Cargo.toml")?,
-        "crucible",
-        &crucible_rev.to_string(),
-    )?;
+    // Keep shared crates.io dependencies aligned with the registry, across all
+    // of the repos. Tracked separately from `update_required`: a workspace almost
+    // always has *some* third-party crate behind latest, and folding that into the
+    // flag the loop below short-circuits on would stop the git-pin and image checks
+    // from ever running.
+    let mut crates_io_update_required = false;
+    let index = GitIndex::new_cargo_default()?;
+    for repo in &config.repos {
+        crates_io_update_required |= compare_cargo_toml_versions(
+            &repo.name,
+            &Manifest::from_path(format!("./{}/Cargo.toml", repo.name))?,
+            &index,
+            mode,
+            reporter,
+        )?;
+    }
 
-    update_required |= compare_cargo_toml_revisions(
-        "omicron",
-        &Manifest::from_path("./omicron/Cargo.toml")?,
-        "propolis",
-        &propolis_rev.to_string(),
-    )?;
+    // Walk the dependency DAG in topological order so an upstream repo's HEAD is
+    // settled before we propagate it into the repos that pin it.
+    // Stale Cargo.lock entries are tracked separately so they don't cut the walk
+    // short before the Cargo.toml pins of later repos have been checked.
+    let mut lock_update_required = false;
+    for repo in topological_order(&config.propagations)? {
+        lock_update_required |= check_cargo_lock_revisions(&repo, &latest_revs, mode, reporter)?;
+
+        for propagation in config.propagations.iter().filter(|p| p.into == repo) {
+            update_required |= compare_cargo_toml_revisions(
+                &repo,
+                &Manifest::from_path(format!("./{}/Cargo.toml", repo))?,
+                &propagation.from,
+                &latest_revs[&propagation.from],
+                mode,
+                reporter,
+            )?;
+        }
 
-    if update_required {
-        return Ok(());
+        if update_required {
+            return Ok(true);
+        }
     }
 
-    // Check if omicron needs to update package-manifest for new crucible and propolis images
-    let package_manifest: Config =
-        toml::from_str(&std::fs::read_to_string("./omicron/package-manifest.toml")?)?;
+    // Check if the manifest repo needs to update package-manifest for new images
+    let skip_image: BTreeMap<&str, bool> = config
+        .repos
+        .iter()
+        .map(|repo| (repo.name.as_str(), repo.skip_image))
+        .collect();
+
+    let manifest_path = format!("./{}/package-manifest.toml", config.manifest_repo);
+    let package_manifest: Config = toml::from_str(&std::fs::read_to_string(&manifest_path)?)?;
 
     for (name, package) in &package_manifest.packages {
         if let PackageSource::Prebuilt {
@@ -386,29 +960,40 @@ fn main() -> Result<()> {
             sha256,
         } = &package.source
         {
-            if !latest_revs.contains_key(&repo.clone()) {
-                println!("no latest rev for {}", repo);
+            if !latest_revs.contains_key(repo) {
+                if reporter.is_text() {
+                    println!("no latest rev for {}", repo);
+                }
                 continue;
             }
 
-            // skip checking maghemite for now
-            if repo == &"maghemite".to_string() {
+            // some repos' images aren't checked yet (e.g. maghemite)
+            if skip_image.get(repo.as_str()).copied().unwrap_or(false) {
                 continue;
             }
 
             // make sure images are built
-            let response = client
-                    .get(&format!("
-                        https://buildomat.eng.oxide.computer/public/file/oxidecomputer/{}/image/{}/{}.sha256.txt",
-                        repo,
-                        latest_revs[&repo.clone()],
-                        name))
-                    .send();
+            let url = config
+                .image_url
+                .replace("{repo}", repo)
+                .replace("{commit}", &latest_revs[repo])
+                .replace("{name}", name);
+            let response = client.get(&url).send();
 
             if let Err(e) = response {
-                println!(
-                    "wait for {} image for {} to be built (reqwest returned {})",
-                    name, propolis_rev, e,
+                reporter.report(
+                    &format!(
+                        "wait for {} image for {} to be built (reqwest returned {})",
+                        name, latest_revs[repo], e,
+                    ),
+                    Finding {
+                        kind: FindingKind::ImagePending,
+                        repo: repo.clone(),
+                        file: url.clone(),
+                        dependency: Some(name.clone()),
+                        from: None,
+                        to: Some(latest_revs[repo].clone()),
+                    },
                 );
                 continue;
             }
@@ -416,41 +1001,117 @@ fn main() -> Result<()> {
             let response = response.unwrap();
 
             if !response.status().is_success() {
-                println!(
-                    "wait for {} image for {} to be built (reqwest returned {})",
-                    name,
-                    propolis_rev,
-                    response.status(),
+                reporter.report(
+                    &format!(
+                        "wait for {} image for {} to be built (reqwest returned {})",
+                        name,
+                        latest_revs[repo],
+                        response.status(),
+                    ),
+                    Finding {
+                        kind: FindingKind::ImagePending,
+                        repo: repo.clone(),
+                        file: url.clone(),
+                        dependency: Some(name.clone()),
+                        from: None,
+                        to: Some(latest_revs[repo].clone()),
+                    },
                 );
                 continue;
             }
 
             let response_hash = response.text()?;
             if response_hash.trim() != sha256 {
-                println!(
-                    "update omicron package manifest {} sha256 from {} to {}",
-                    name,
-                    sha256,
-                    response_hash.trim()
+                reporter.report(
+                    &format!(
+                        "update {} package manifest {} sha256 from {} to {}",
+                        config.manifest_repo,
+                        name,
+                        sha256,
+                        response_hash.trim()
+                    ),
+                    Finding {
+                        kind: FindingKind::ManifestSha256,
+                        repo: repo.clone(),
+                        file: manifest_path.clone(),
+                        dependency: Some(name.clone()),
+                        from: Some(sha256.clone()),
+                        to: Some(response_hash.trim().to_string()),
+                    },
                 );
+                if mode == Mode::Apply {
+                    set_toml_value(
+                        &manifest_path,
+                        &["package", name, "source", "sha256"],
+                        response_hash.trim(),
+                    )?;
+                }
                 update_required = true;
             }
 
             // make sure rev is up to date
-            if *commit != latest_revs[&repo.clone()] {
-                println!(
-                    "update omicron package manifest {} rev from {} to {}",
-                    name,
-                    commit,
-                    latest_revs[&repo.clone()]
+            if *commit != latest_revs[repo] {
+                reporter.report(
+                    &format!(
+                        "update {} package manifest {} rev from {} to {}",
+                        config.manifest_repo,
+                        name,
+                        commit,
+                        latest_revs[repo]
+                    ),
+                    Finding {
+                        kind: FindingKind::ManifestRev,
+                        repo: repo.clone(),
+                        file: manifest_path.clone(),
+                        dependency: Some(name.clone()),
+                        from: Some(commit.clone()),
+                        to: Some(latest_revs[repo].clone()),
+                    },
                 );
+                if mode == Mode::Apply {
+                    set_toml_value(
+                        &manifest_path,
+                        &["package", name, "source", "commit"],
+                        &latest_revs[repo],
+                    )?;
+                }
                 update_required = true;
             }
         }
     }
 
+    Ok(update_required || crates_io_update_required || lock_update_required)
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+
+    // `--dry-run` (the default) only prints the changes that are required;
+    // `--apply` also rewrites the manifests in place.
+    let mode = if args.iter().any(|arg| arg == "--apply") {
+        Mode::Apply
+    } else {
+        Mode::DryRun
+    };
+
+    // `--format json` emits the findings as a JSON array for CI and other
+    // tooling; the default is free-form English.
+    let format = if args.windows(2).any(|w| w[0] == "--format" && w[1] == "json")
+        || args.iter().any(|arg| arg == "--format=json")
+    {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Text
+    };
+
+    let mut reporter = Reporter::new(format);
+    let update_required = run(mode, &mut reporter)?;
+    reporter.finish()?;
+
+    // exit nonzero when an update is required, so lockstep can gate CI the way
+    // `cargo update --dry-run` aborts with a warning
     if update_required {
-        return Ok(());
+        std::process::exit(1);
     }
 
     Ok(())